@@ -0,0 +1,240 @@
+/*
+HTTP/2 and h2c request path, used when `-h2` or `--h2c` is given.
+
+Unlike the HTTP/1.1 path (one `hyper::Client` connection pooled per
+request), a worker here opens exactly one TCP connection, negotiates
+HTTP/2 over it (via ALPN for `-h2`, or with prior knowledge for
+`--h2c`), and multiplexes every claimed unit of work as a new stream on
+that single connection. `-c` therefore controls the number of
+independent HTTP/2 connections rather than the number of in-flight
+streams.
+*/
+
+extern crate h2;
+extern crate http;
+extern crate native_tls;
+extern crate tokio_tls;
+
+use std::io;
+use std::net::ToSocketAddrs;
+use std::time::Instant;
+
+use futures::{Future, Stream};
+use h2::client::SendRequest;
+use hyper::Uri;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+
+use metrics::{ErrorKind, RequestMetric};
+
+/// Which HTTP/2 variant a worker negotiated; carried through purely to
+/// label the metrics emitted over that connection.
+#[derive(Clone, Copy)]
+pub enum Mode {
+    Tls,
+    Cleartext,
+}
+
+impl Mode {
+    fn label(&self) -> &'static str {
+        match *self {
+            Mode::Tls => "HTTP/2",
+            Mode::Cleartext => "h2c",
+        }
+    }
+}
+
+/// A single multiplexed HTTP/2 connection, reused by one worker for
+/// every request it sends.
+pub struct Connection {
+    send_request: SendRequest<::bytes::Bytes>,
+    mode: Mode,
+}
+
+fn resolve(uri: &Uri) -> io::Result<::std::net::SocketAddr> {
+    let host = uri.host().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "url has no host"))?;
+    let port = uri.port().unwrap_or(if uri.scheme() == Some("https") { 443 } else { 80 });
+    (host, port).to_socket_addrs()?.next().ok_or_else(|| io::Error::new(io::ErrorKind::Other, "could not resolve host"))
+}
+
+/// Open one HTTP/2 connection to `uri`: TLS with ALPN `h2` unless
+/// `h2c` is set, in which case the connection is established in
+/// cleartext using HTTP/2 prior knowledge (no upgrade dance).
+pub fn connect(uri: Uri, h2c: bool, handle: Handle) -> Box<Future<Item = Connection, Error = ErrorKind>> {
+    let addr = match resolve(&uri) {
+        Ok(a) => a,
+        Err(_) => return Box::new(::futures::future::err(ErrorKind::Dns)),
+    };
+
+    if h2c {
+        let fut = TcpStream::connect(&addr, &handle)
+            .map_err(|_| ErrorKind::Connect)
+            .and_then(|tcp| h2::client::handshake(tcp).map_err(|_| ErrorKind::Other("h2 handshake failed".to_owned())))
+            .map(move |(send_request, connection)| {
+                handle.spawn(connection.map_err(|_| ()));
+                Connection { send_request: send_request, mode: Mode::Cleartext }
+            });
+        Box::new(fut)
+    } else {
+        let host = uri.host().unwrap_or("").to_owned();
+        let fut = TcpStream::connect(&addr, &handle)
+            .map_err(|_| ErrorKind::Connect)
+            .and_then(move |tcp| {
+                let mut builder = native_tls::TlsConnector::builder();
+                builder.request_alpns(&["h2"]);
+                let connector = builder.build().map_err(|_| ErrorKind::Tls)?;
+                let connector: ::tokio_tls::TlsConnector = connector.into();
+                Ok(connector.connect_async(&host, tcp))
+            })
+            .and_then(|handshake| handshake.map_err(|_| ErrorKind::Tls))
+            .and_then(move |tls| h2::client::handshake(tls).map_err(|_| ErrorKind::Other("h2 handshake failed".to_owned())))
+            .map(move |(send_request, connection)| {
+                handle.spawn(connection.map_err(|_| ()));
+                Connection { send_request: send_request, mode: Mode::Tls }
+            });
+        Box::new(fut)
+    }
+}
+
+/// Send one request as a new stream over `conn`, returning the updated
+/// connection (so the caller can keep reusing it) alongside the metric
+/// for this stream. Any `-d`/`-D` body is buffered up front (`req`'s
+/// body is already a fully in-memory `Vec<u8>`, set by `Options::get_request`)
+/// and, if non-empty, written as a single DATA frame after headers.
+pub fn send(conn: Connection, req: ::hyper::Request) -> Box<Future<Item = (Connection, RequestMetric), Error = ()>> {
+    let method = http::Method::from_bytes(req.method().as_ref().as_bytes()).unwrap_or(http::Method::GET);
+
+    // h2's `:authority` pseudo-header is derived from the URI handed to
+    // the builder below, not from a literal `host` header, so a `-host`
+    // override (which `Options::get_request` applies as a `Host`
+    // header) would otherwise be silently dropped on this path. Fold it
+    // into the URI's authority here instead.
+    let host_header = req.headers().get_raw("Host")
+        .and_then(|raw| raw.one())
+        .map(|v| String::from_utf8_lossy(v).into_owned());
+    let uri_string = match host_header {
+        Some(ref host) => {
+            let scheme = req.uri().scheme().unwrap_or("https");
+            let query = req.uri().query().map(|q| format!("?{}", q)).unwrap_or_default();
+            format!("{}://{}{}{}", scheme, host, req.uri().path(), query)
+        }
+        None => req.uri().to_string(),
+    };
+    let uri: http::Uri = uri_string.parse().unwrap_or_else(|_| http::Uri::from_static("/"));
+
+    let mut builder = http::Request::builder();
+    builder.method(method).uri(uri);
+    for header in req.headers().iter() {
+        // Already folded into `:authority` above; forwarding it too as
+        // a literal header would just duplicate it.
+        if header.name().eq_ignore_ascii_case("host") {
+            continue;
+        }
+        builder.header(header.name(), header.value_string().as_str());
+    }
+
+    Box::new(req.body().concat2().then(move |body_result| -> Box<Future<Item = (Connection, RequestMetric), Error = ()>> {
+        let body = body_result.map(|chunk| chunk.to_vec()).unwrap_or_default();
+        send_on_stream(conn, builder, body)
+    }))
+}
+
+/// Build the h2 request from `builder`, dispatch it (writing `body` as
+/// a DATA frame when non-empty), and time the response. Split out of
+/// `send` so the body can be buffered asynchronously first.
+fn send_on_stream(
+    conn: Connection,
+    mut builder: http::request::Builder,
+    body: Vec<u8>,
+) -> Box<Future<Item = (Connection, RequestMetric), Error = ()>> {
+    let http_req = match builder.body(()) {
+        Ok(r) => r,
+        Err(_) => return Box::new(::futures::future::ok((conn, RequestMetric {
+            status: None,
+            latency: ::std::time::Duration::new(0, 0),
+            bytes: 0,
+            wire_bytes: 0,
+            error: Some(ErrorKind::Other("could not build h2 request".to_owned())),
+            protocol: conn.mode.label(),
+            phases: None,
+        }))),
+    };
+
+    let start = Instant::now();
+    let label = conn.mode.label();
+    let Connection { mut send_request, mode } = conn;
+
+    let (response_future, mut send_stream) = match send_request.send_request(http_req, body.is_empty()) {
+        Ok(pair) => pair,
+        Err(_) => {
+            let metric = RequestMetric {
+                status: None,
+                latency: start.elapsed(),
+                bytes: 0,
+                wire_bytes: 0,
+                error: Some(ErrorKind::Other("h2 stream refused".to_owned())),
+                protocol: label,
+                phases: None,
+            };
+            return Box::new(::futures::future::ok((Connection { send_request: send_request, mode: mode }, metric)));
+        }
+    };
+
+    if !body.is_empty() {
+        if let Err(_) = send_stream.send_data(::bytes::Bytes::from(body), true) {
+            let metric = RequestMetric {
+                status: None,
+                latency: start.elapsed(),
+                bytes: 0,
+                wire_bytes: 0,
+                error: Some(ErrorKind::Other("h2 body write failed".to_owned())),
+                protocol: label,
+                phases: None,
+            };
+            return Box::new(::futures::future::ok((Connection { send_request: send_request, mode: mode }, metric)));
+        }
+    }
+
+    Box::new(response_future.then(move |result| {
+        let latency = start.elapsed();
+        let conn = Connection { send_request: send_request, mode: mode };
+        match result {
+            Ok(response) => {
+                let status = response.status().as_u16();
+                let content_encoding = response.headers().get(http::header::CONTENT_ENCODING)
+                    .and_then(|v| v.to_str().ok()).map(|s| s.to_owned());
+                Box::new(response.into_body().fold(Vec::new(), |mut wire, chunk| {
+                    wire.extend_from_slice(&chunk);
+                    Ok::<Vec<u8>, h2::Error>(wire)
+                }).then(move |wire_result| {
+                    let wire = wire_result.unwrap_or_default();
+                    let wire_bytes = wire.len() as u64;
+                    let bytes = ::compression::decode(content_encoding.as_ref().map(String::as_str), &wire)
+                        .map(|d| d.len() as u64).unwrap_or(wire_bytes);
+                    let metric = RequestMetric {
+                        status: Some(status),
+                        latency: latency,
+                        bytes: bytes,
+                        wire_bytes: wire_bytes,
+                        error: None,
+                        protocol: label,
+                        phases: None,
+                    };
+                    Ok((conn, metric))
+                })) as Box<Future<Item = (Connection, RequestMetric), Error = ()>>
+            }
+            Err(_) => {
+                let metric = RequestMetric {
+                    status: None,
+                    latency: latency,
+                    bytes: 0,
+                    wire_bytes: 0,
+                    error: Some(ErrorKind::Other("h2 stream error".to_owned())),
+                    protocol: label,
+                    phases: None,
+                };
+                Box::new(::futures::future::ok((conn, metric)))
+            }
+        }
+    }))
+}
@@ -0,0 +1,57 @@
+/*
+Leaky-bucket rate limiter used to pace request admission at a steady
+`rps` rather than releasing `creq` requests in a single burst every
+second.
+*/
+
+use std::time::{Duration, Instant};
+
+/// Token bucket with capacity `creq` that refills at `rps` tokens/sec.
+/// `rps == 0` means unbounded: `try_admit` always succeeds immediately.
+pub struct RateLimiter {
+    capacity: f64,
+    rps: f64,
+    available: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(capacity: u32, rps: u32) -> RateLimiter {
+        RateLimiter {
+            capacity: capacity as f64,
+            rps: rps as f64,
+            available: capacity as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn unbounded(&self) -> bool {
+        self.rps <= 0.0
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill);
+        let elapsed_secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1_000_000_000.0;
+        self.available = (self.available + elapsed_secs * self.rps).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    /// Try to admit a single request. Returns `Ok(())` if a token was
+    /// available and has been consumed, or `Err(wait)` with how long to
+    /// wait before the next attempt would succeed.
+    pub fn try_admit(&mut self) -> Result<(), Duration> {
+        if self.unbounded() {
+            return Ok(());
+        }
+        self.refill();
+        if self.available >= 1.0 {
+            self.available -= 1.0;
+            Ok(())
+        } else {
+            let deficit = 1.0 - self.available;
+            let wait_secs = deficit / self.rps;
+            Err(Duration::new(wait_secs as u64, (wait_secs.fract() * 1_000_000_000.0) as u32))
+        }
+    }
+}
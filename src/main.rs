@@ -8,7 +8,10 @@
     -q  Rate limit, in seconds (QPS).
     -o  Output type. If none provided, a summary is printed.
     "csv" is the only supported alternative. Dumps the response
-    metrics in comma-separated values format.
+    metrics in comma-separated values format, one row per request
+    (no header row): status,latency_ms,bytes,wire_bytes,error.
+    bytes is the decoded body size, wire_bytes is what actually
+    crossed the wire (they differ under a compressed response).
 
     -m  HTTP method, one of GET, POST, PUT, DELETE, HEAD, OPTIONS.
     -H  Custom HTTP header. You can specify as many as needed by repeating the flag.
@@ -19,17 +22,37 @@
     -D  HTTP request body from file. For example, /home/user/file.txt or ./file.txt.
     -T  Content-type, defaults to "text/html".
     -a  Basic authentication, username:password.
-    -x  HTTP Proxy address as host:port.
-    -h2 Enable HTTP/2.
+    -x  HTTP Proxy address as host:port. Not implemented yet; rhi
+    exits with an error rather than silently ignoring it.
+    -h2 Enable HTTP/2 (TLS, negotiated via ALPN).
+    --h2c Enable HTTP/2 cleartext with prior knowledge (no TLS).
 
     -host HTTP Host header.
 
-    -disable-compression  Disable compression.
+    -disable-compression  Disable compression. By default, requests advertise
+    "Accept-Encoding: gzip, br" and gzip/brotli response bodies are
+    decompressed before their size is counted.
     -disable-keepalive    Disable keep-alive, prevents re-use of TCP
     connections between different HTTP requests.
     -cpus                 Number of used cpu cores.
     (default for current machine is 8 cores)
     -more                 Provides information on DNS lookup, dialup, request and response timings.
+    Not combined with -h2/--h2c; -h2/--h2c take priority if both are given.
+
+    --stop-on-error  Stop the run as soon as a request hits a fatal error
+    (connection refused, DNS failure, TLS error).
+    --fail-on-status With --stop-on-error, also treat any non-2xx response
+    status as fatal.
+
+    --duration <secs>           Run continuously for this many seconds
+    instead of a fixed number of requests (-n is ignored), printing a
+    rolling snapshot every --snapshot-interval.
+    --snapshot-interval <secs>  With --duration, how often to print a
+    rolling metrics snapshot and, with --prometheus, push the run's
+    cumulative metrics to the pushgateway. Default is 5.
+    --prometheus <host:port>    With --duration, push the run's cumulative
+    metrics to a Prometheus pushgateway at every snapshot, in text
+    exposition format.
 */
 
 
@@ -40,17 +63,35 @@ extern crate tokio_core;
 extern crate clap;
 extern crate url;
 extern crate core;
+extern crate bytes;
+
+mod metrics;
+mod limiter;
+mod pool;
+mod h2_client;
+mod timing;
+mod compression;
 
 use futures::{Future,Stream};
+use futures::future::{self, Loop};
+use futures::sync::mpsc;
 use hyper::{Method, Request, Client, Uri};
 use hyper::header::{ContentLength, Accept, QualityItem, Authorization, Basic};
-use tokio_core::reactor::{Core, Interval};
+use tokio_core::reactor::{Core, Handle, Timeout};
 use clap::{Arg, App, ArgMatches};
 use core::str::FromStr;
-use std::time::{Duration};
+use std::time::{Duration, Instant};
 use std::fmt;
+use std::fs;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, Ordering};
 use core::num::ParseIntError;
 
+use limiter::RateLimiter;
+use metrics::{ErrorKind, RequestMetric};
+use pool::WorkCounter;
+
 const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 const DEFAULT_NREQ: u32 = 100;
 const DEFAULT_CREQ: u32 = 10;
@@ -69,7 +110,11 @@ struct Options<'a>{
     rps: u32, 
 
     // timeout per request.
-    timeout: Duration, 
+    timeout: Duration,
+
+    // -D request body, read from disk once at startup rather than
+    // per request; None if -D wasn't given.
+    body: Option<Vec<u8>>,
 
     matches: ArgMatches<'a>,
 }
@@ -101,6 +146,12 @@ impl<'a> Options<'a>{
             req.headers_mut().set(Accept(vec![qi]));
         }
 
+        // Advertise gzip/brotli support unless the caller asked us not
+        // to decompress responses.
+        if !self.matches.is_present("disable compression") {
+            req.headers_mut().set_raw("Accept-Encoding", "gzip, br");
+        }
+
         // Basic authorization.
         if self.matches.is_present("a") {
             let v: Vec<&str> = self.matches.value_of("a").unwrap().split(':').collect();
@@ -115,12 +166,41 @@ impl<'a> Options<'a>{
             ))
         }
 
-        // Body.
-        if self.matches.is_present("d") {
-            let body = self.matches.value_of("d").unwrap().to_owned();
+        // Host override.
+        if self.matches.is_present("host") {
+            let host = self.matches.value_of("host").unwrap().to_owned();
+            req.headers_mut().set_raw("Host", host);
+        }
+
+        // Custom headers, "Name: Value", repeatable.
+        if let Some(headers) = self.matches.values_of("H") {
+            for header in headers {
+                let idx = match header.find(':') {
+                    Some(idx) => idx,
+                    None => continue,
+                };
+                let name = header[..idx].trim().to_owned();
+                let value = header[idx + 1..].trim().to_owned();
+                req.headers_mut().set_raw(name, value);
+            }
+        }
+
+        // Body, from a file via -D (cached once in `self.body` by
+        // `get_options`, takes precedence) or inline via -d.
+        let body = if let Some(ref bytes) = self.body {
+            Some(bytes.clone())
+        } else if self.matches.is_present("d") {
+            Some(self.matches.value_of("d").unwrap().as_bytes().to_owned())
+        } else {
+            None
+        };
+
+        if let Some(body) = body {
             let blen = body.len();
             req.set_body(body);
             req.headers_mut().set(ContentLength(blen as u64));
+            let content_type = self.matches.value_of("T").unwrap_or("text/html").to_owned();
+            req.headers_mut().set_raw("Content-Type", content_type);
         }
 
         req
@@ -136,36 +216,462 @@ impl<'a> fmt::Display for Options<'a> {
     
 }
 
+/// Issue `req` on `client`, timing it and turning the outcome into a
+/// `RequestMetric` for the caller to forward and inspect.
+fn perform_request<C>(client: &Client<C>, req: Request) -> Box<Future<Item = RequestMetric, Error = ()>>
+    where C: hyper::client::Connect
+{
+    let start = Instant::now();
+    Box::new(client.request(req).then(move |result| -> Box<Future<Item = RequestMetric, Error = ()>> {
+        let latency = start.elapsed();
+        match result {
+            Ok(res) => {
+                let status = res.status().as_u16();
+                let content_encoding = content_encoding_of(&res);
+                Box::new(res.body().concat2().then(move |body| {
+                    let (bytes, wire_bytes) = decoded_sizes(content_encoding.as_ref().map(String::as_str), body);
+                    Ok(RequestMetric {
+                        status: Some(status),
+                        latency: latency,
+                        bytes: bytes,
+                        wire_bytes: wire_bytes,
+                        error: None,
+                        protocol: "HTTP/1.1",
+                        phases: None,
+                    })
+                }))
+            }
+            Err(e) => {
+                Box::new(future::ok(RequestMetric {
+                    status: None,
+                    latency: latency,
+                    bytes: 0,
+                    wire_bytes: 0,
+                    error: Some(ErrorKind::classify(&e)),
+                    protocol: "HTTP/1.1",
+                    phases: None,
+                }))
+            }
+        }
+    }))
+}
+
+/// Response's `Content-Encoding`, if any, read before the body future
+/// consumes the response.
+fn content_encoding_of(res: &::hyper::Response) -> Option<String> {
+    res.headers().get_raw("Content-Encoding")
+        .and_then(|raw| raw.one())
+        .map(|v| String::from_utf8_lossy(v).into_owned())
+}
+
+/// Decompress a fully-buffered body (if `encoding` names one `rhi`
+/// understands) and return `(decoded_bytes, wire_bytes)`. A body read
+/// error is treated the same way the pre-existing code already did:
+/// zero bytes, status/error left to the caller.
+fn decoded_sizes(encoding: Option<&str>, body: Result<::hyper::Chunk, ::hyper::Error>) -> (u64, u64) {
+    match body {
+        Ok(chunk) => {
+            let wire_bytes = chunk.len() as u64;
+            let bytes = compression::decode(encoding, &chunk).map(|d| d.len() as u64).unwrap_or(wire_bytes);
+            (bytes, wire_bytes)
+        }
+        Err(_) => (0, 0),
+    }
+}
+
+/// Like `perform_request`, but for the `-more` path: the connection
+/// phases dialed for this request (if any were dialed at all, rather
+/// than reusing a pooled connection) are read back out of `slot` and
+/// combined with timestamps taken when the request is handed to the
+/// client (before any dial), when the response head arrives, and when
+/// its body has been received.
+fn perform_request_timed(
+    client: &Client<timing::TimingConnector>,
+    req: Request,
+    slot: timing::ConnectSlot,
+) -> Box<Future<Item = RequestMetric, Error = ()>> {
+    slot.borrow_mut().take();
+    let start = Instant::now();
+    Box::new(client.request(req).then(move |result| -> Box<Future<Item = RequestMetric, Error = ()>> {
+        let ttfb = Instant::now();
+        match result {
+            Ok(res) => {
+                let status = res.status().as_u16();
+                let content_encoding = content_encoding_of(&res);
+                Box::new(res.body().concat2().then(move |body| {
+                    let done = Instant::now();
+                    let (bytes, wire_bytes) = decoded_sizes(content_encoding.as_ref().map(String::as_str), body);
+                    let mut phases = slot.borrow_mut().take().unwrap_or_default();
+                    // `start` precedes the dial, so on a freshly-dialed
+                    // connection `ttfb - start` also covers DNS/connect/TLS;
+                    // subtract them back out (a no-op on a reused
+                    // connection, where they're already zero) to isolate
+                    // request-sent -> first-byte.
+                    phases.processing = (ttfb - start) - phases.dns - phases.connect - phases.tls;
+                    phases.transfer = done - ttfb;
+                    Ok(RequestMetric {
+                        status: Some(status),
+                        latency: done - start,
+                        bytes: bytes,
+                        wire_bytes: wire_bytes,
+                        error: None,
+                        protocol: "HTTP/1.1",
+                        phases: Some(phases),
+                    })
+                }))
+            }
+            Err(e) => {
+                let mut phases = slot.borrow_mut().take().unwrap_or_default();
+                phases.processing = (ttfb - start) - phases.dns - phases.connect - phases.tls;
+                Box::new(future::ok(RequestMetric {
+                    status: None,
+                    latency: ttfb - start,
+                    bytes: 0,
+                    wire_bytes: 0,
+                    error: Some(ErrorKind::classify(&e)),
+                    protocol: "HTTP/1.1",
+                    phases: Some(phases),
+                }))
+            }
+        }
+    }))
+}
+
+/// A request is considered fatal for `--stop-on-error` purposes if it
+/// never reached the server (connection, DNS or TLS failure), or if
+/// `--fail-on-status` is set and the response was outside the 2xx range.
+fn is_fatal(metric: &RequestMetric, fail_on_status: bool) -> bool {
+    match metric.error {
+        Some(ErrorKind::Connect) | Some(ErrorKind::Dns) | Some(ErrorKind::Tls) => true,
+        _ => fail_on_status && metric.status.map_or(false, |s| s < 200 || s >= 300),
+    }
+}
+
+fn describe_failure(metric: &RequestMetric) -> String {
+    match metric.error {
+        Some(ref kind) => kind.to_string(),
+        None => format!("status {}", metric.status.unwrap_or(0)),
+    }
+}
+
+/// Build one worker: it repeatedly claims a unit of work from `counter`,
+/// waits for `limiter` to admit it, and dispatches a request, stopping
+/// as soon as the counter is exhausted or `abort` is set. Several of
+/// these run side by side (via `join_all` in `main`) to give `creq`
+/// concurrency.
+fn run_worker<C>(
+    handle: Handle,
+    client: Client<C>,
+    opts: Rc<Options<'static>>,
+    counter: Rc<WorkCounter>,
+    limiter: Rc<RefCell<RateLimiter>>,
+    metrics_tx: mpsc::Sender<RequestMetric>,
+    stop_on_error: bool,
+    fail_on_status: bool,
+    abort: Rc<AtomicBool>,
+    abort_reason: Rc<RefCell<Option<String>>>,
+) -> Box<Future<Item = (), Error = ()>>
+    where C: hyper::client::Connect
+{
+    Box::new(future::loop_fn((), move |_| {
+        if abort.load(Ordering::SeqCst) {
+            return future::Either::A(future::ok(Loop::Break(())));
+        }
+        match limiter.borrow_mut().try_admit() {
+            Ok(()) => {
+                if !counter.claim() {
+                    return future::Either::A(future::ok(Loop::Break(())));
+                }
+                let req = opts.get_request();
+                let tx = metrics_tx.clone();
+                let abort = abort.clone();
+                let abort_reason = abort_reason.clone();
+                let fut = perform_request(&client, req).and_then(move |metric| {
+                    let fatal = stop_on_error && is_fatal(&metric, fail_on_status);
+                    if fatal && !abort.swap(true, Ordering::SeqCst) {
+                        *abort_reason.borrow_mut() = Some(describe_failure(&metric));
+                    }
+                    tx.send(metric).then(move |_| {
+                        Ok(if fatal { Loop::Break(()) } else { Loop::Continue(()) })
+                    })
+                });
+                future::Either::B(Box::new(fut) as Box<Future<Item = Loop<(), ()>, Error = ()>>)
+            }
+            Err(wait) => {
+                let timeout = Timeout::new(wait, &handle).unwrap()
+                    .then(|_| Ok(Loop::Continue(())));
+                future::Either::B(Box::new(timeout) as Box<Future<Item = Loop<(), ()>, Error = ()>>)
+            }
+        }
+    }))
+}
+
+/// Like `run_worker`, but for the `-h2`/`--h2c` path: a single
+/// multiplexed HTTP/2 connection is opened once and then threaded
+/// through the loop as its state, reused for every claimed request.
+fn run_worker_h2(
+    handle: Handle,
+    uri: Uri,
+    h2c: bool,
+    opts: Rc<Options<'static>>,
+    counter: Rc<WorkCounter>,
+    limiter: Rc<RefCell<RateLimiter>>,
+    metrics_tx: mpsc::Sender<RequestMetric>,
+    stop_on_error: bool,
+    fail_on_status: bool,
+    abort: Rc<AtomicBool>,
+    abort_reason: Rc<RefCell<Option<String>>>,
+) -> Box<Future<Item = (), Error = ()>> {
+    let connect_handle = handle.clone();
+    let connect_tx = metrics_tx.clone();
+    let protocol = if h2c { "h2c" } else { "HTTP/2" };
+    Box::new(h2_client::connect(uri, h2c, connect_handle).then(move |result| -> Box<Future<Item = (), Error = ()>> {
+        let conn = match result {
+            Ok(conn) => conn,
+            Err(e) => {
+                // Mirror the h1 path: a connect/handshake failure becomes
+                // a failed RequestMetric rather than an Err that would
+                // make the joined `join_all` cancel every sibling worker
+                // (and the aggregator) with no metrics and no summary.
+                let metric = RequestMetric {
+                    status: None,
+                    latency: Duration::new(0, 0),
+                    bytes: 0,
+                    wire_bytes: 0,
+                    error: Some(e),
+                    protocol: protocol,
+                    phases: None,
+                };
+                if stop_on_error && is_fatal(&metric, fail_on_status) && !abort.swap(true, Ordering::SeqCst) {
+                    *abort_reason.borrow_mut() = Some(describe_failure(&metric));
+                }
+                return Box::new(connect_tx.send(metric).then(|_| Ok(())));
+            }
+        };
+        Box::new(future::loop_fn(conn, move |conn| {
+            if abort.load(Ordering::SeqCst) {
+                return future::Either::A(future::ok(Loop::Break(())));
+            }
+            match limiter.borrow_mut().try_admit() {
+                Ok(()) => {
+                    if !counter.claim() {
+                        return future::Either::A(future::ok(Loop::Break(())));
+                    }
+                    let req = opts.get_request();
+                    let tx = metrics_tx.clone();
+                    let abort = abort.clone();
+                    let abort_reason = abort_reason.clone();
+                    let fut = h2_client::send(conn, req).and_then(move |(conn, metric)| {
+                        let fatal = stop_on_error && is_fatal(&metric, fail_on_status);
+                        if fatal && !abort.swap(true, Ordering::SeqCst) {
+                            *abort_reason.borrow_mut() = Some(describe_failure(&metric));
+                        }
+                        tx.send(metric).then(move |_| {
+                            Ok(if fatal { Loop::Break(()) } else { Loop::Continue(conn) })
+                        })
+                    });
+                    future::Either::B(Box::new(fut) as Box<Future<Item = Loop<(), h2_client::Connection>, Error = ()>>)
+                }
+                Err(wait) => {
+                    let timeout = Timeout::new(wait, &handle).unwrap()
+                        .then(move |_| Ok(Loop::Continue(conn)));
+                    future::Either::B(Box::new(timeout) as Box<Future<Item = Loop<(), h2_client::Connection>, Error = ()>>)
+                }
+            }
+        }))
+    }))
+}
+
+/// Like `run_worker`, but for `-more`: the worker gets its own
+/// `Client` built on a `timing::TimingConnector` (rather than sharing
+/// the plain one built in `main`), so that the `ConnectSlot` it reads
+/// after every request is never written to by another worker's
+/// connection.
+fn run_worker_timed(
+    handle: Handle,
+    opts: Rc<Options<'static>>,
+    counter: Rc<WorkCounter>,
+    limiter: Rc<RefCell<RateLimiter>>,
+    metrics_tx: mpsc::Sender<RequestMetric>,
+    stop_on_error: bool,
+    fail_on_status: bool,
+    abort: Rc<AtomicBool>,
+    abort_reason: Rc<RefCell<Option<String>>>,
+    keepalive: bool,
+) -> Box<Future<Item = (), Error = ()>> {
+    let slot: timing::ConnectSlot = Rc::new(RefCell::new(None));
+    let connector = timing::TimingConnector::new(handle.clone(), slot.clone());
+    let client = Client::configure().connector(connector).keep_alive(keepalive).build(&handle);
+
+    Box::new(future::loop_fn((), move |_| {
+        if abort.load(Ordering::SeqCst) {
+            return future::Either::A(future::ok(Loop::Break(())));
+        }
+        match limiter.borrow_mut().try_admit() {
+            Ok(()) => {
+                if !counter.claim() {
+                    return future::Either::A(future::ok(Loop::Break(())));
+                }
+                let req = opts.get_request();
+                let tx = metrics_tx.clone();
+                let abort = abort.clone();
+                let abort_reason = abort_reason.clone();
+                let fut = perform_request_timed(&client, req, slot.clone()).and_then(move |metric| {
+                    let fatal = stop_on_error && is_fatal(&metric, fail_on_status);
+                    if fatal && !abort.swap(true, Ordering::SeqCst) {
+                        *abort_reason.borrow_mut() = Some(describe_failure(&metric));
+                    }
+                    tx.send(metric).then(move |_| {
+                        Ok(if fatal { Loop::Break(()) } else { Loop::Continue(()) })
+                    })
+                });
+                future::Either::B(Box::new(fut) as Box<Future<Item = Loop<(), ()>, Error = ()>>)
+            }
+            Err(wait) => {
+                let timeout = Timeout::new(wait, &handle).unwrap()
+                    .then(|_| Ok(Loop::Continue(())));
+                future::Either::B(Box::new(timeout) as Box<Future<Item = Loop<(), ()>, Error = ()>>)
+            }
+        }
+    }))
+}
+
 fn main() {
 
-    let opts = get_options().unwrap();
+    let opts = Rc::new(get_options().unwrap());
+    let csv_out = opts.matches.value_of("o") == Some("csv");
+    let stop_on_error = opts.matches.is_present("stop-on-error");
+    let fail_on_status = opts.matches.is_present("fail-on-status");
+    let h2_enabled = opts.matches.is_present("h2") || opts.matches.is_present("h2c");
+    let h2c = opts.matches.is_present("h2c");
+    let more = opts.matches.is_present("more") && !h2_enabled;
+    let keepalive = !opts.matches.is_present("disable-keepalive");
+    let duration = opts.matches.value_of("duration").map(|s| s.parse::<u64>().unwrap());
+    let snapshot_interval = opts.matches.value_of("snapshot-interval").unwrap().parse::<u64>().unwrap();
+    let prometheus = opts.matches.value_of("prometheus").map(|s| s.to_owned());
+
+    // -x is accepted by the parser but nothing wires it into the
+    // connector yet; fail loudly at startup rather than silently
+    // sending every request straight to the target unproxied.
+    if opts.matches.is_present("x") {
+        eprintln!("rhi: -x (HTTP proxy) is not implemented yet; refusing to silently ignore it.");
+        ::std::process::exit(1);
+    }
 
     let mut core = Core::new().unwrap();
     let core_handle = core.handle();
     let client = Client::new(&core_handle);
 
-    let ticks = Interval::new(Duration::new(1, 0), &core_handle).unwrap();
-    let ticks_future = ticks.for_each( move |_| {
-
-        // Send creq requests to server per second.
-        let mut c = 0;
-        while c < opts.creq {
-            c += 1;
-            let req = opts.get_request();
-            let post = client.request(req).and_then(|res| {
-                println!("response: {}", res.status());
-                res.body().concat2()
-            }).then(|_| Ok(()) );
-            core_handle.spawn(post);
-        }
+    let (metrics_tx, metrics_rx) = mpsc::channel::<RequestMetric>(1024);
+    let aggregator = match duration {
+        Some(_) => metrics::run_aggregator_continuous(
+            metrics_rx,
+            Duration::from_secs(snapshot_interval),
+            core_handle.clone(),
+            prometheus,
+            client.clone(),
+            csv_out,
+        ),
+        None => metrics::run_aggregator(metrics_rx, csv_out),
+    };
 
-        Ok(())
+    // `--duration` runs against a wall-clock deadline instead of a fixed
+    // request budget, so the counter never runs dry on its own.
+    let counter = Rc::new(match duration {
+        Some(_) => WorkCounter::unbounded(),
+        None => WorkCounter::new(opts.nreq),
+    });
+    let limiter = Rc::new(RefCell::new(RateLimiter::new(opts.creq, opts.rps)));
+    let abort = Rc::new(AtomicBool::new(false));
+    let abort_reason = Rc::new(RefCell::new(None));
+
+    // Exactly `creq` workers share the `nreq` work budget; `nreq` total
+    // requests are issued no matter how it is split across them (fewer
+    // if `--stop-on-error` aborts the run), and
+    // the run ends (and the summary prints) once every worker has
+    // drained the counter, hit the abort flag, or its in-flight request
+    // has resolved. Under `-h2`/`--h2c`, `-c` is the number of HTTP/2
+    // connections rather than the number of in-flight HTTP/1.1 requests.
+    let mut workers: Vec<Box<Future<Item = (), Error = ()>>> = (0..opts.creq).map(|_| {
+        if h2_enabled {
+            let uri = Uri::from_str(opts.matches.value_of("url").unwrap()).unwrap();
+            run_worker_h2(
+                core_handle.clone(),
+                uri,
+                h2c,
+                opts.clone(),
+                counter.clone(),
+                limiter.clone(),
+                metrics_tx.clone(),
+                stop_on_error,
+                fail_on_status,
+                abort.clone(),
+                abort_reason.clone(),
+            )
+        } else if more {
+            run_worker_timed(
+                core_handle.clone(),
+                opts.clone(),
+                counter.clone(),
+                limiter.clone(),
+                metrics_tx.clone(),
+                stop_on_error,
+                fail_on_status,
+                abort.clone(),
+                abort_reason.clone(),
+                keepalive,
+            )
+        } else {
+            run_worker(
+                core_handle.clone(),
+                client.clone(),
+                opts.clone(),
+                counter.clone(),
+                limiter.clone(),
+                metrics_tx.clone(),
+                stop_on_error,
+                fail_on_status,
+                abort.clone(),
+                abort_reason.clone(),
+            )
+        }
+    }).collect();
+
+    // Under `--duration`, every worker just shares the `abort` flag
+    // already used by `--stop-on-error`; this future is the one thing
+    // that sets it once the wall-clock deadline elapses, the same way a
+    // fatal error sets it under `--stop-on-error`.
+    if let Some(secs) = duration {
+        let abort = abort.clone();
+        let deadline = Timeout::new(Duration::from_secs(secs), &core_handle).unwrap()
+            .then(move |_| {
+                abort.store(true, Ordering::SeqCst);
+                Ok(())
+            });
+        workers.push(Box::new(deadline));
+    }
 
+    // Every worker already holds its own clone of `metrics_tx` (passed
+    // in above), so this last one can be dropped now instead of after
+    // the workers are joined. That matters: the aggregator has to be
+    // polled concurrently with the workers to drain the channel (and,
+    // under `--duration`, to print rolling snapshots as the run goes),
+    // not just after they finish -- chaining it with `.then()` after
+    // `join_all` would leave it unpolled until every worker already
+    // completed, deadlocking on a full channel well before that (and,
+    // under `--duration`, never printing a snapshot at all).
+    drop(metrics_tx);
+
+    let run = future::join_all(workers).join(aggregator).then(move |_| {
+        if let Some(reason) = abort_reason.borrow_mut().take() {
+            println!("\nAborted early by --stop-on-error: {}", reason);
+        }
+        Ok::<(), ()>(())
     });
-    core.run(ticks_future).unwrap();
+    core.run(run).unwrap();
 }
 
-fn get_options<'a>() -> Result<Options<'a>, ParseIntError> {
+fn get_options() -> Result<Options<'static>, ParseIntError> {
 
     let app = App::new("rhi").version(VERSION)
                     .about("HTTP load generator (like hey by @rakyll)")
@@ -183,7 +689,12 @@ be smaller than the concurrency level."))
                         .short("q")
                         .takes_value(true)
                         .default_value("1")
-                        .help("Rate limit, in seconds (QPS)"))
+                        .help("Rate limit, in queries per second (QPS). Use 0 for unbounded."))
+                    .arg(Arg::with_name("o")
+                        .short("o")
+                        .takes_value(true)
+                        .possible_values(&["csv"])
+                        .help("Output type. If none provided, a summary is printed. \"csv\" dumps one row per request, no header, as status,latency_ms,bytes,wire_bytes,error."))
                     .arg(Arg::with_name("method")
                         .short("m")
                         .long("method")
@@ -206,16 +717,60 @@ be smaller than the concurrency level."))
                         .short("d")
                         .takes_value(true)
                         .help("HTTP request body."))
+                    .arg(Arg::with_name("D")
+                        .short("D")
+                        .takes_value(true)
+                        .help("HTTP request body from file. For example, /home/user/file.txt or ./file.txt."))
+                    .arg(Arg::with_name("T")
+                        .short("T")
+                        .takes_value(true)
+                        .help("Content-type, defaults to \"text/html\"."))
                     .arg(Arg::with_name("a")
                         .short("a")
                         .takes_value(true)
                         .help("Basic authentication, username:password."))
+                    .arg(Arg::with_name("x")
+                        .short("x")
+                        .takes_value(true)
+                        .help("HTTP Proxy address as host:port. Not implemented yet; rhi exits with an error rather than silently ignoring it."))
+                    .arg(Arg::with_name("host")
+                        .long("host")
+                        .takes_value(true)
+                        .help("HTTP Host header."))
                     .arg(Arg::with_name("disable compression")
                         .long("disable-compression")
                         .help("Disable compression."))
                     .arg(Arg::with_name("disable-keepalive")
                         .long("disable-keepalive")
                         .help("Disable keep-alive, prevents re-use of TCP connections between different HTTP requests."))
+                    .arg(Arg::with_name("stop-on-error")
+                        .long("stop-on-error")
+                        .help("Stop the run as soon as a request hits a fatal error (connection refused, DNS failure, TLS error)."))
+                    .arg(Arg::with_name("fail-on-status")
+                        .long("fail-on-status")
+                        .help("With --stop-on-error, also treat any non-2xx response status as fatal."))
+                    .arg(Arg::with_name("h2")
+                        .long("h2")
+                        .help("Enable HTTP/2 (TLS, negotiated via ALPN)."))
+                    .arg(Arg::with_name("h2c")
+                        .long("h2c")
+                        .help("Enable HTTP/2 cleartext with prior knowledge (no TLS)."))
+                    .arg(Arg::with_name("more")
+                        .long("more")
+                        .help("Provides information on DNS lookup, dialup, request and response timings. Not combined with -h2/--h2c."))
+                    .arg(Arg::with_name("duration")
+                        .long("duration")
+                        .takes_value(true)
+                        .help("Run continuously for this many seconds instead of a fixed number of requests (-n is ignored), printing a rolling snapshot every --snapshot-interval."))
+                    .arg(Arg::with_name("snapshot-interval")
+                        .long("snapshot-interval")
+                        .takes_value(true)
+                        .default_value("5")
+                        .help("With --duration, how often (in seconds) to print a rolling metrics snapshot and, with --prometheus, push the run's cumulative metrics to the pushgateway."))
+                    .arg(Arg::with_name("prometheus")
+                        .long("prometheus")
+                        .takes_value(true)
+                        .help("With --duration, push the run's cumulative metrics to a Prometheus pushgateway at this host:port at every snapshot, in text exposition format."))
                     .arg(Arg::with_name("url")
                         .help("url to hit")
                         .required(true)
@@ -236,11 +791,26 @@ be smaller than the concurrency level."))
         None => DEFAULT_RPS,
     };
 
+    // Read the -D body file once here, at startup, rather than per
+    // request from inside the hot path. A read failure is a startup
+    // error, not something a load test should discover mid-run.
+    let body = match matches.value_of("D") {
+        Some(path) => match fs::read(path) {
+            Ok(bytes) => Some(bytes),
+            Err(e) => {
+                eprintln!("rhi: could not read body file {}: {}", path, e);
+                ::std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
     let options = Options{
         nreq: nreq,
         creq: creq,
         rps: rps,
         timeout: Duration::new(10,0),
+        body: body,
         matches: matches,
     };
 
@@ -0,0 +1,508 @@
+/*
+Latency/statistics subsystem.
+
+Each completed request is turned into a `RequestMetric` and sent over a
+`futures::sync::mpsc` channel to an aggregator future that runs on the
+`Core` alongside the request generator. The aggregator folds the stream
+into a `Summary`, optionally emitting one CSV row per request as they
+arrive, and prints a `hey`-style report once the stream ends.
+
+Percentiles are estimated from a fixed exponential-bucket histogram
+rather than by keeping every sample in memory, since a run can easily
+produce millions of latencies.
+*/
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use futures::{Future, Stream};
+use futures::future::{self, Either, Loop};
+use futures::stream::StreamFuture;
+use futures::sync::mpsc::Receiver;
+use tokio_core::reactor::{Handle, Interval};
+
+const HIST_BUCKETS: usize = 100;
+const HIST_MIN_MS: f64 = 1.0;
+const HIST_MAX_MS: f64 = 60_000.0;
+
+fn duration_to_ms(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1_000.0 + d.subsec_nanos() as f64 / 1_000_000.0
+}
+
+/// Coarse classification of why a request failed, used both for the
+/// status-code-like histogram in the summary and for fatal-error
+/// detection by the abort-on-error worker logic.
+#[derive(Debug, Clone)]
+pub enum ErrorKind {
+    Connect,
+    Dns,
+    Tls,
+    Timeout,
+    Other(String),
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ErrorKind::Connect => write!(f, "connect"),
+            ErrorKind::Dns => write!(f, "dns"),
+            ErrorKind::Tls => write!(f, "tls"),
+            ErrorKind::Timeout => write!(f, "timeout"),
+            ErrorKind::Other(ref s) => write!(f, "{}", s),
+        }
+    }
+}
+
+impl ErrorKind {
+    /// Best-effort classification of a `hyper::Error` into one of the
+    /// buckets above, based on the underlying io error kind and the
+    /// error's own message (hyper 0.11 does not expose a DNS-specific
+    /// variant, so DNS failures are sniffed out of the message text).
+    pub fn classify(err: &::hyper::Error) -> ErrorKind {
+        let msg = err.to_string();
+        let lower = msg.to_lowercase();
+        if lower.contains("dns") || lower.contains("resolve") || lower.contains("lookup") {
+            return ErrorKind::Dns;
+        }
+        if lower.contains("tls") || lower.contains("ssl") || lower.contains("certificate") {
+            return ErrorKind::Tls;
+        }
+        if let &::hyper::Error::Io(ref io_err) = err {
+            match io_err.kind() {
+                io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted | io::ErrorKind::NotConnected => {
+                    return ErrorKind::Connect;
+                }
+                io::ErrorKind::TimedOut => return ErrorKind::Timeout,
+                _ => {}
+            }
+        }
+        if lower.contains("timed out") || lower.contains("timeout") {
+            return ErrorKind::Timeout;
+        }
+        ErrorKind::Other(msg)
+    }
+}
+
+/// Per-phase connection timing for a single request, recorded by the
+/// `-more` code path. `dns`/`connect`/`tls` are zero when the request
+/// reused a pooled connection instead of dialing a fresh one.
+#[derive(Clone, Default)]
+pub struct PhaseTimings {
+    pub dns: Duration,
+    pub connect: Duration,
+    pub tls: Duration,
+    pub processing: Duration,
+    pub transfer: Duration,
+}
+
+/// Outcome of a single request, sent from a worker to the aggregator.
+/// `bytes` is the response body size after decompression; `wire_bytes`
+/// is what actually crossed the wire. The two differ whenever the
+/// response carried a `Content-Encoding` we understood.
+pub struct RequestMetric {
+    pub status: Option<u16>,
+    pub latency: Duration,
+    pub bytes: u64,
+    pub wire_bytes: u64,
+    pub error: Option<ErrorKind>,
+    pub protocol: &'static str,
+    pub phases: Option<PhaseTimings>,
+}
+
+impl RequestMetric {
+    /// One `-o csv` row, no header: `status,latency_ms,bytes,wire_bytes,error`.
+    /// `wire_bytes` sits alongside `bytes` rather than replacing it so a
+    /// compressed response's on-wire size stays visible even after
+    /// decoding -- see `RequestMetric`'s doc comment.
+    fn to_csv_row(&self) -> String {
+        let status = self.status.map(|s| s.to_string()).unwrap_or_default();
+        let error = self.error.as_ref().map(|e| e.to_string()).unwrap_or_default();
+        format!("{},{},{},{},{}", status, duration_to_ms(self.latency), self.bytes, self.wire_bytes, error)
+    }
+}
+
+/// Fixed exponential-bucket histogram spanning `HIST_MIN_MS..HIST_MAX_MS`,
+/// used to estimate percentiles without retaining every sample.
+struct Histogram {
+    buckets: [u64; HIST_BUCKETS],
+    ratio: f64,
+}
+
+/// Mean plus percentile estimate for one phase of the connection
+/// lifecycle (DNS, connect, TLS, ...), tracked alongside the overall
+/// latency histogram but only populated under `-more`.
+#[derive(Default)]
+struct PhaseAccumulator {
+    histogram_buckets: Option<Histogram>,
+    sum_ms: f64,
+    count: u64,
+}
+
+impl PhaseAccumulator {
+    fn record(&mut self, d: Duration) {
+        let ms = duration_to_ms(d);
+        self.sum_ms += ms;
+        self.count += 1;
+        self.histogram_buckets.get_or_insert_with(Histogram::new).record(ms);
+    }
+
+    fn mean(&self) -> f64 {
+        if self.count == 0 { 0.0 } else { self.sum_ms / self.count as f64 }
+    }
+
+    fn percentile(&self, p: f64) -> f64 {
+        match self.histogram_buckets {
+            Some(ref h) => h.percentile(p, self.count),
+            None => 0.0,
+        }
+    }
+}
+
+/// Aggregate per-phase stats for a run, printed under `-more`.
+#[derive(Default)]
+struct PhaseStats {
+    dns: PhaseAccumulator,
+    connect: PhaseAccumulator,
+    tls: PhaseAccumulator,
+    processing: PhaseAccumulator,
+    transfer: PhaseAccumulator,
+}
+
+impl PhaseStats {
+    fn record(&mut self, t: &PhaseTimings) {
+        self.dns.record(t.dns);
+        self.connect.record(t.connect);
+        self.tls.record(t.tls);
+        self.processing.record(t.processing);
+        self.transfer.record(t.transfer);
+    }
+
+    fn print(&self) {
+        println!();
+        println!("Per-phase timing (mean / p50 / p99, ms):");
+        let phases: [(&str, &PhaseAccumulator); 5] = [
+            ("DNS lookup", &self.dns),
+            ("TCP connect", &self.connect),
+            ("TLS handshake", &self.tls),
+            ("Server processing", &self.processing),
+            ("Content transfer", &self.transfer),
+        ];
+        for &(label, acc) in phases.iter() {
+            println!("  {:<18}\t{:.3}\t{:.3}\t{:.3}", label, acc.mean(), acc.percentile(0.50), acc.percentile(0.99));
+        }
+    }
+}
+
+impl Histogram {
+    fn new() -> Histogram {
+        Histogram {
+            buckets: [0; HIST_BUCKETS],
+            ratio: (HIST_MAX_MS / HIST_MIN_MS).powf(1.0 / HIST_BUCKETS as f64),
+        }
+    }
+
+    fn bucket_for(&self, ms: f64) -> usize {
+        if ms <= HIST_MIN_MS {
+            return 0;
+        }
+        let idx = (ms / HIST_MIN_MS).ln() / self.ratio.ln();
+        (idx.floor() as usize).min(HIST_BUCKETS - 1)
+    }
+
+    fn bucket_range(&self, i: usize) -> (f64, f64) {
+        (HIST_MIN_MS * self.ratio.powi(i as i32), HIST_MIN_MS * self.ratio.powi(i as i32 + 1))
+    }
+
+    fn record(&mut self, ms: f64) {
+        let i = self.bucket_for(ms);
+        self.buckets[i] += 1;
+    }
+
+    /// Estimate the `p`th percentile (0.0..1.0) by walking cumulative
+    /// bucket counts until the target rank falls inside a bucket, then
+    /// interpolating linearly across that bucket's range.
+    fn percentile(&self, p: f64, total: u64) -> f64 {
+        if total == 0 {
+            return 0.0;
+        }
+        let target = ((p * total as f64).ceil() as u64).max(1);
+        let mut cumulative = 0u64;
+        for (i, &count) in self.buckets.iter().enumerate() {
+            cumulative += count;
+            if cumulative >= target {
+                if count == 0 {
+                    return self.bucket_range(i).0;
+                }
+                let (lo, hi) = self.bucket_range(i);
+                let within = (target - (cumulative - count)) as f64 / count as f64;
+                return lo + within * (hi - lo);
+            }
+        }
+        HIST_MAX_MS
+    }
+}
+
+/// Running totals and distribution for a run. Built up incrementally by
+/// the aggregator as `RequestMetric`s arrive.
+pub struct Summary {
+    started: Instant,
+    count: u64,
+    errors: u64,
+    total_bytes: u64,
+    total_wire_bytes: u64,
+    sum_ms: f64,
+    min_ms: f64,
+    max_ms: f64,
+    status_counts: BTreeMap<u16, u64>,
+    error_counts: BTreeMap<String, u64>,
+    histogram: Histogram,
+    protocol: Option<&'static str>,
+    phase_stats: Option<PhaseStats>,
+}
+
+impl Summary {
+    fn new() -> Summary {
+        Summary {
+            started: Instant::now(),
+            count: 0,
+            errors: 0,
+            total_bytes: 0,
+            total_wire_bytes: 0,
+            sum_ms: 0.0,
+            min_ms: ::std::f64::MAX,
+            max_ms: 0.0,
+            status_counts: BTreeMap::new(),
+            error_counts: BTreeMap::new(),
+            histogram: Histogram::new(),
+            protocol: None,
+            phase_stats: None,
+        }
+    }
+
+    fn record(&mut self, m: &RequestMetric) {
+        let ms = duration_to_ms(m.latency);
+        self.count += 1;
+        self.protocol = Some(m.protocol);
+        self.total_bytes += m.bytes;
+        self.total_wire_bytes += m.wire_bytes;
+        self.sum_ms += ms;
+        self.min_ms = self.min_ms.min(ms);
+        self.max_ms = self.max_ms.max(ms);
+        self.histogram.record(ms);
+        if let Some(ref phases) = m.phases {
+            self.phase_stats.get_or_insert_with(PhaseStats::default).record(phases);
+        }
+
+        match m.status {
+            Some(status) => {
+                *self.status_counts.entry(status).or_insert(0) += 1;
+            }
+            None => {
+                self.errors += 1;
+                let key = m.error.as_ref().map(|e| e.to_string()).unwrap_or_else(|| "unknown".to_owned());
+                *self.error_counts.entry(key).or_insert(0) += 1;
+            }
+        }
+    }
+
+    fn print_report(&self) {
+        let elapsed = duration_to_ms(self.started.elapsed()) / 1_000.0;
+        let rps = if elapsed > 0.0 { self.count as f64 / elapsed } else { 0.0 };
+        let mean_ms = if self.count > 0 { self.sum_ms / self.count as f64 } else { 0.0 };
+        let min_ms = if self.count > 0 { self.min_ms } else { 0.0 };
+
+        println!();
+        println!("Summary:");
+        if let Some(protocol) = self.protocol {
+            println!("  Protocol:\t\t{}", protocol);
+        }
+        println!("  Total requests:\t{}", self.count);
+        println!("  Requests/sec:\t\t{:.4}", rps);
+        println!("  Total bytes (decoded):\t{}", self.total_bytes);
+        println!("  Total bytes (wire):\t{}", self.total_wire_bytes);
+        let compression_ratio = if self.total_bytes > 0 { self.total_wire_bytes as f64 / self.total_bytes as f64 } else { 1.0 };
+        println!("  Compression ratio:\t{:.4} (wire/decoded)", compression_ratio);
+        println!();
+        println!("Latency distribution:");
+        println!("  min\t{:.3} ms", min_ms);
+        println!("  mean\t{:.3} ms", mean_ms);
+        println!("  max\t{:.3} ms", self.max_ms);
+        for p in &[0.50, 0.90, 0.95, 0.99] {
+            println!("  p{:<3}\t{:.3} ms", (p * 100.0) as u32, self.histogram.percentile(*p, self.count));
+        }
+        println!();
+        println!("Status code distribution:");
+        for (status, count) in &self.status_counts {
+            println!("  [{}]\t{} responses", status, count);
+        }
+        if self.errors > 0 {
+            println!();
+            println!("Error distribution:");
+            for (kind, count) in &self.error_counts {
+                println!("  [{}]\t{} errors", kind, count);
+            }
+        }
+        if let Some(ref phase_stats) = self.phase_stats {
+            phase_stats.print();
+        }
+    }
+
+    /// Terser one-line report for a rolling window, printed every
+    /// `--snapshot-interval` in continuous (`--duration`) mode. The
+    /// caller resets the window to a fresh `Summary` right after.
+    fn print_snapshot(&self) {
+        let elapsed = duration_to_ms(self.started.elapsed()) / 1_000.0;
+        let rps = if elapsed > 0.0 { self.count as f64 / elapsed } else { 0.0 };
+        let error_rate = if self.count > 0 { self.errors as f64 / self.count as f64 } else { 0.0 };
+        println!(
+            "[snapshot] requests={}\trps={:.2}\terrors={}\terror_rate={:.4}\tp50={:.3}ms\tp90={:.3}ms\tp99={:.3}ms",
+            self.count, rps, self.errors, error_rate,
+            self.histogram.percentile(0.50, self.count),
+            self.histogram.percentile(0.90, self.count),
+            self.histogram.percentile(0.99, self.count),
+        );
+    }
+
+    /// Render this `Summary` as a Prometheus text-exposition body:
+    /// request counts by status code plus a latency histogram using the
+    /// internal `Histogram`'s own bucket boundaries as the `le` labels,
+    /// the way `hey`/`vegeta`-style tools report into a pushgateway.
+    /// Called on the run's cumulative `Summary`, not a rolling window,
+    /// so the `counter`-typed series stay monotonic across pushes.
+    fn to_prometheus_text(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# TYPE rhi_requests_total counter\n");
+        for (status, count) in &self.status_counts {
+            out.push_str(&format!("rhi_requests_total{{status=\"{}\"}} {}\n", status, count));
+        }
+        out.push_str("# TYPE rhi_errors_total counter\n");
+        out.push_str(&format!("rhi_errors_total {}\n", self.errors));
+
+        out.push_str("# TYPE rhi_request_duration_ms histogram\n");
+        let mut cumulative = 0u64;
+        for i in 0..HIST_BUCKETS {
+            cumulative += self.histogram.buckets[i];
+            let (_, upper) = self.histogram.bucket_range(i);
+            out.push_str(&format!("rhi_request_duration_ms_bucket{{le=\"{:.3}\"}} {}\n", upper, cumulative));
+        }
+        out.push_str(&format!("rhi_request_duration_ms_bucket{{le=\"+Inf\"}} {}\n", self.count));
+        out.push_str(&format!("rhi_request_duration_ms_sum {:.3}\n", self.sum_ms));
+        out.push_str(&format!("rhi_request_duration_ms_count {}\n", self.count));
+        out
+    }
+}
+
+/// Best-effort PUT of `body` to a Prometheus pushgateway at `addr`
+/// (`host:port`) under job `rhi`. A pushgateway being briefly
+/// unreachable shouldn't abort a long-running soak test, so failures
+/// are logged to stderr and otherwise swallowed.
+fn push_to_prometheus<C>(client: &::hyper::Client<C>, addr: &str, body: String) -> Box<Future<Item = (), Error = ()>>
+    where C: ::hyper::client::Connect
+{
+    let uri = match ::hyper::Uri::from_str(&format!("http://{}/metrics/job/rhi", addr)) {
+        Ok(u) => u,
+        Err(e) => {
+            eprintln!("prometheus push skipped, bad --prometheus address {}: {}", addr, e);
+            return Box::new(future::ok(()));
+        }
+    };
+    let mut req = ::hyper::Request::new(::hyper::Method::Put, uri);
+    req.headers_mut().set(::hyper::header::ContentLength(body.len() as u64));
+    req.set_body(body);
+    Box::new(client.request(req).then(|result| {
+        if let Err(e) = result {
+            eprintln!("prometheus push failed: {}", e);
+        }
+        Ok(())
+    }))
+}
+
+/// Continuous (`--duration`) counterpart to `run_aggregator`: a rolling
+/// window `Summary` is printed every `snapshot_interval` then reset,
+/// while a second `Summary` accumulates the whole run; with `prometheus`
+/// set, that cumulative one is pushed to a pushgateway at every interval,
+/// and it is printed as one final report once the channel closes, the
+/// same as the one-shot `-n` mode's report.
+pub fn run_aggregator_continuous<C>(
+    rx: Receiver<RequestMetric>,
+    snapshot_interval: Duration,
+    handle: Handle,
+    prometheus: Option<String>,
+    push_client: ::hyper::Client<C>,
+    csv_out: bool,
+) -> Box<Future<Item = (), Error = ()>>
+    where C: ::hyper::client::Connect
+{
+    let ticks = match Interval::new(snapshot_interval, &handle) {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("could not start --snapshot-interval timer: {}", e);
+            return Box::new(future::err(()));
+        }
+    };
+    // `select2` hands back the still-pending side as an unresolved
+    // `StreamFuture`, not the bare stream, so both sides of the loop
+    // state are kept as `StreamFuture`s throughout (the winning side is
+    // re-armed with `.into_future()` before the next iteration).
+    let state = (rx.into_future(), ticks.into_future(), Summary::new(), Summary::new());
+    Box::new(future::loop_fn(state, move |(rx, ticks, mut window, mut cumulative)| {
+        let prometheus = prometheus.clone();
+        let push_client = push_client.clone();
+        type State = (StreamFuture<Receiver<RequestMetric>>, StreamFuture<Interval>, Summary, Summary);
+        rx.select2(ticks).then(move |res| -> Box<Future<Item = Loop<(), State>, Error = ()>> {
+            match res {
+                Ok(Either::A(((item, rx), ticks))) => match item {
+                    Some(m) => {
+                        if csv_out {
+                            let stdout = io::stdout();
+                            let mut handle = stdout.lock();
+                            let _ = writeln!(handle, "{}", m.to_csv_row());
+                        }
+                        window.record(&m);
+                        cumulative.record(&m);
+                        Box::new(future::ok(Loop::Continue((rx.into_future(), ticks, window, cumulative))))
+                    }
+                    None => {
+                        window.print_snapshot();
+                        cumulative.print_report();
+                        Box::new(future::ok(Loop::Break(())))
+                    }
+                },
+                Ok(Either::B(((_tick, ticks), rx))) => {
+                    window.print_snapshot();
+                    // Pushed as the run's running totals, not this
+                    // window's, since `rhi_requests_total`/`rhi_errors_total`
+                    // are declared `counter` -- a pushgateway metric under
+                    // that type has to be monotonic non-decreasing, or
+                    // rate()/increase() over it misbehaves. `window` stays
+                    // the source for the terminal snapshot above.
+                    let push = match prometheus {
+                        Some(ref addr) => push_to_prometheus(&push_client, addr, cumulative.to_prometheus_text()),
+                        None => Box::new(future::ok(())) as Box<Future<Item = (), Error = ()>>,
+                    };
+                    Box::new(push.then(move |_| Ok(Loop::Continue((rx, ticks.into_future(), Summary::new(), cumulative)))))
+                }
+                Err(_) => Box::new(future::ok(Loop::Break(()))),
+            }
+        })
+    }))
+}
+
+/// Consume the metrics channel for the lifetime of the run, writing a
+/// CSV row per request as they arrive (when `csv_out` is set) and
+/// printing the aggregate summary once the channel closes.
+pub fn run_aggregator(rx: Receiver<RequestMetric>, csv_out: bool) -> Box<Future<Item = (), Error = ()>> {
+    let stdout = io::stdout();
+    Box::new(rx.fold(Summary::new(), move |mut summary, m| {
+        if csv_out {
+            let mut handle = stdout.lock();
+            let _ = writeln!(handle, "{}", m.to_csv_row());
+        }
+        summary.record(&m);
+        Ok(summary)
+    }).map(|summary| summary.print_report()))
+}
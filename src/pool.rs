@@ -0,0 +1,36 @@
+/*
+Bounded worker pool: caps the total number of requests issued across a
+run at `nreq`, shared out between `creq` concurrent workers.
+*/
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Remaining-work budget shared by every worker. `claim` atomically
+/// reserves one unit of work, returning `false` once the budget is
+/// spent so a worker knows to stop picking up new requests.
+pub struct WorkCounter(AtomicUsize);
+
+impl WorkCounter {
+    pub fn new(total: u32) -> WorkCounter {
+        WorkCounter(AtomicUsize::new(total as usize))
+    }
+
+    /// A counter that never runs out, for continuous (`--duration`) runs
+    /// where the stopping condition is a wall-clock deadline rather than
+    /// a fixed request count.
+    pub fn unbounded() -> WorkCounter {
+        WorkCounter(AtomicUsize::new(usize::max_value()))
+    }
+
+    pub fn claim(&self) -> bool {
+        loop {
+            let current = self.0.load(Ordering::SeqCst);
+            if current == 0 {
+                return false;
+            }
+            if self.0.compare_and_swap(current, current - 1, Ordering::SeqCst) == current {
+                return true;
+            }
+        }
+    }
+}
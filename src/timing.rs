@@ -0,0 +1,162 @@
+/*
+Per-phase connection timing for the `-more` flag.
+
+`hyper::Client` pools connections and only exposes the `Connect` trait
+as a seam for customizing how a fresh one is dialed; a reused, pooled
+connection never calls `connect()` again, which conveniently matches
+the requirement that DNS/connect/TLS be reported as zero whenever a
+request rides an existing connection. Neither the stock `HttpConnector`
+nor `hyper_tls::HttpsConnector` expose the DNS/TCP/TLS boundaries
+individually, so `TimingConnector` dials the socket itself (mirroring
+`h2_client::connect`'s dial sequence rather than wrapping either of
+them) and stamps an `Instant` at each boundary, recording the result
+into the slot the caller resets before every request.
+
+Time-to-first-byte and body-received happen on the stream after
+dialing, where a `Connect` impl can no longer see them, so the caller
+timestamps the request start (before any dial), the response head and
+the end of the body around `Client::request` and the response/body
+futures, the same way `perform_request` already times overall latency.
+*/
+
+extern crate native_tls;
+extern crate tokio_io;
+extern crate tokio_tls;
+
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use futures::{Future, Poll};
+use hyper::client::{Connect, Connected, Destination};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use metrics::PhaseTimings;
+
+/// Slot a worker resets (to `None`) before every request and reads
+/// back afterward. Left empty when the request reused a pooled
+/// connection, since `connect()` was never called for it.
+pub type ConnectSlot = Rc<RefCell<Option<PhaseTimings>>>;
+
+/// Either side of a connection `TimingConnector` may hand back,
+/// depending on whether the destination was `http` or `https`.
+pub enum Transport {
+    Plain(TcpStream),
+    Tls(tokio_tls::TlsStream<TcpStream>),
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Transport::Plain(ref mut s) => s.read(buf),
+            Transport::Tls(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Transport::Plain(ref mut s) => s.write(buf),
+            Transport::Tls(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Transport::Plain(ref mut s) => s.flush(),
+            Transport::Tls(ref mut s) => s.flush(),
+        }
+    }
+}
+
+impl AsyncRead for Transport {}
+
+impl AsyncWrite for Transport {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match *self {
+            Transport::Plain(ref mut s) => AsyncWrite::shutdown(s),
+            Transport::Tls(ref mut s) => AsyncWrite::shutdown(s),
+        }
+    }
+}
+
+fn resolve(dst: &Destination) -> io::Result<SocketAddr> {
+    let port = dst.port().unwrap_or(if dst.scheme() == "https" { 443 } else { 80 });
+    (dst.host(), port).to_socket_addrs()?.next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "could not resolve host"))
+}
+
+/// A from-scratch `Connect` implementation, one per `-more` worker, so
+/// that each worker's own sequential request loop owns its own
+/// `ConnectSlot` without racing another worker's connects.
+pub struct TimingConnector {
+    handle: Handle,
+    slot: ConnectSlot,
+}
+
+impl TimingConnector {
+    pub fn new(handle: Handle, slot: ConnectSlot) -> TimingConnector {
+        TimingConnector { handle: handle, slot: slot }
+    }
+}
+
+impl Connect for TimingConnector {
+    type Transport = Transport;
+    type Error = io::Error;
+    type Future = Box<Future<Item = (Transport, Connected), Error = io::Error>>;
+
+    fn connect(&self, dst: Destination) -> Self::Future {
+        let https = dst.scheme() == "https";
+        let host = dst.host().to_owned();
+        let slot = self.slot.clone();
+        let handle = self.handle.clone();
+
+        let dns_start = Instant::now();
+        let addr = match resolve(&dst) {
+            Ok(a) => a,
+            Err(e) => return Box::new(::futures::future::err(e)),
+        };
+        let dns_done = Instant::now();
+
+        Box::new(TcpStream::connect(&addr, &handle).and_then(move |tcp| {
+            let connect_done = Instant::now();
+
+            if !https {
+                *slot.borrow_mut() = Some(PhaseTimings {
+                    dns: dns_done - dns_start,
+                    connect: connect_done - dns_done,
+                    tls: Duration::new(0, 0),
+                    processing: Duration::new(0, 0),
+                    transfer: Duration::new(0, 0),
+                });
+                return Box::new(::futures::future::ok((Transport::Plain(tcp), Connected::new())))
+                    as Box<Future<Item = (Transport, Connected), Error = io::Error>>;
+            }
+
+            let connector = match native_tls::TlsConnector::new() {
+                Ok(c) => c,
+                Err(e) => return Box::new(::futures::future::err(io::Error::new(io::ErrorKind::Other, e)))
+                    as Box<Future<Item = (Transport, Connected), Error = io::Error>>,
+            };
+            let connector: tokio_tls::TlsConnector = connector.into();
+            Box::new(connector.connect_async(&host, tcp)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+                .map(move |tls| {
+                    let tls_done = Instant::now();
+                    *slot.borrow_mut() = Some(PhaseTimings {
+                        dns: dns_done - dns_start,
+                        connect: connect_done - dns_done,
+                        tls: tls_done - connect_done,
+                        processing: Duration::new(0, 0),
+                        transfer: Duration::new(0, 0),
+                    });
+                    (Transport::Tls(tls), Connected::new())
+                })) as Box<Future<Item = (Transport, Connected), Error = io::Error>>
+        }))
+    }
+}